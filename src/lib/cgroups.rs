@@ -0,0 +1,75 @@
+//! cgroup v2 resource limits applied to a job's process, so a single runaway command
+//! (this crate runs arbitrary user commands) can't starve the host of CPU, memory or
+//! process-table slots.
+
+use crate::errors::RLWServerError;
+
+use std::fs;
+use std::path::PathBuf;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Matches `cpu.max`'s period column; `cpu_shares` is then the allowed quota within it.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Resource limits applied to a job's process via a dedicated cgroup v2 directory.
+#[derive(Debug, Clone, Copy)]
+pub struct JobLimits {
+    /// Microseconds of CPU time allowed per `CPU_PERIOD_US` window, written to `cpu.max`.
+    pub cpu_shares: u64,
+    /// Hard memory ceiling in bytes, written to `memory.max`.
+    pub memory_bytes: u64,
+    /// Maximum number of processes/threads the job may fork, written to `pids.max`.
+    pub pids_max: u64,
+}
+
+impl Default for JobLimits {
+    fn default() -> Self {
+        Self {
+            cpu_shares: CPU_PERIOD_US,
+            memory_bytes: 512 * 1024 * 1024,
+            pids_max: 256,
+        }
+    }
+}
+
+/// A cgroup v2 directory created for a single job's process.
+pub struct JobCgroup {
+    path: PathBuf,
+}
+
+impl JobCgroup {
+    /// Creates a dedicated cgroup under `CGROUP_ROOT` named after the job's pid and
+    /// applies `limits` to it.
+    pub fn create(pid: u32, limits: JobLimits) -> Result<Self, RLWServerError> {
+        let path = PathBuf::from(CGROUP_ROOT).join(format!("rlw-job-{}", pid));
+        fs::create_dir(&path)
+            .map_err(|e| RLWServerError(format!("Unable to create cgroup {:?}: {:?}", path, e)))?;
+
+        fs::write(path.join("memory.max"), limits.memory_bytes.to_string())
+            .map_err(|e| RLWServerError(format!("Unable to set memory.max: {:?}", e)))?;
+        fs::write(
+            path.join("cpu.max"),
+            format!("{} {}", limits.cpu_shares, CPU_PERIOD_US),
+        )
+        .map_err(|e| RLWServerError(format!("Unable to set cpu.max: {:?}", e)))?;
+        fs::write(path.join("pids.max"), limits.pids_max.to_string())
+            .map_err(|e| RLWServerError(format!("Unable to set pids.max: {:?}", e)))?;
+
+        Ok(Self { path })
+    }
+
+    /// Moves `pid` into this cgroup by writing it to `cgroup.procs`.
+    pub fn add_process(&self, pid: u32) -> Result<(), RLWServerError> {
+        fs::write(self.path.join("cgroup.procs"), pid.to_string()).map_err(|e| {
+            RLWServerError(format!("Unable to move pid {} into cgroup: {:?}", pid, e))
+        })
+    }
+
+    /// Removes the cgroup directory. Only succeeds once the kernel has emptied
+    /// `cgroup.procs`, i.e. after the job's process has exited.
+    pub fn remove(&self) -> Result<(), RLWServerError> {
+        fs::remove_dir(&self.path)
+            .map_err(|e| RLWServerError(format!("Unable to remove cgroup {:?}: {:?}", self.path, e)))
+    }
+}
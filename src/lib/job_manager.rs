@@ -0,0 +1,94 @@
+//! Registry of concurrently running jobs, keyed by `Uuid`, so the gRPC service can
+//! start, list, query, stream and stop many jobs rather than modeling only one `Job`.
+
+use crate::cgroups::JobLimits;
+use crate::errors::RLWServerError;
+use crate::job_processor::status_response;
+use crate::jobs::{CommandType, Job};
+use crate::processing::JobSpec;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::Stream;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+// Grace period given to a job between `SIGTERM` and `SIGKILL` when stopped through
+// the manager; see `Job::stop`.
+const STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// Tracks every job started through it, keyed by the `Uuid` handed back from `start`.
+pub struct JobManager {
+    jobs: Mutex<HashMap<Uuid, Arc<Job>>>,
+}
+
+impl JobManager {
+    /// Construct an empty registry.
+    pub fn new() -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts a new job and returns its id immediately. The process keeps running on a
+    /// spawned task rather than blocking the caller until it exits.
+    ///
+    /// # Arguments
+    ///
+    /// * `spec`   - Command, arguments, working directory and environment for the job.
+    /// * `limits` - Resource caps applied to the process via cgroups. The process runs
+    ///   uncapped when `None`, and also if the host can't set up the requested cgroup.
+    pub async fn start(&self, spec: JobSpec, limits: Option<JobLimits>) -> Uuid {
+        let id = Uuid::new_v4();
+        let job = Arc::new(Job::new());
+        self.jobs.lock().await.insert(id, Arc::clone(&job));
+
+        tokio::spawn(async move {
+            if let Err(e) = job.new_command(spec, CommandType::Start, limits).await {
+                eprintln!("Job {} finished with an error: {:?}", id, e);
+            }
+        });
+
+        id
+    }
+
+    /// Stops a job, escalating from a graceful `SIGTERM` to a forceful `SIGKILL` if it
+    /// doesn't exit in time; see `Job::stop`.
+    pub async fn stop(&self, id: Uuid) -> Result<(), RLWServerError> {
+        self.get(id).await?.stop(STOP_GRACE).await
+    }
+
+    /// Returns the current status of a job.
+    pub async fn status(
+        &self,
+        id: Uuid,
+    ) -> Result<Option<status_response::ProcessStatus>, RLWServerError> {
+        Ok(self.get(id).await?.status.lock().await.clone())
+    }
+
+    /// Streams a job's output; see `Job::stream_output`.
+    pub async fn stream(&self, id: Uuid) -> Result<impl Stream<Item = Vec<u8>>, RLWServerError> {
+        Ok(self.get(id).await?.stream_output().await)
+    }
+
+    /// Lists every job currently tracked along with its status.
+    pub async fn list(&self) -> Vec<(Uuid, Option<status_response::ProcessStatus>)> {
+        let jobs = self.jobs.lock().await;
+        let mut statuses = Vec::with_capacity(jobs.len());
+        for (id, job) in jobs.iter() {
+            statuses.push((*id, job.status.lock().await.clone()));
+        }
+        statuses
+    }
+
+    async fn get(&self, id: Uuid) -> Result<Arc<Job>, RLWServerError> {
+        self.jobs
+            .lock()
+            .await
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| RLWServerError(format!("No job found for id {}", id)))
+    }
+}
@@ -1,20 +1,31 @@
 //! Exposes all the required types and type impls for the
 //! rlw server to run.
 
-use tokio::sync::Mutex;
+use futures::stream::{self, Stream, StreamExt};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use tokio::sync::{broadcast, watch, Mutex};
 use tonic::codegen::http::status;
 
+use crate::cgroups::JobLimits;
 use crate::errors::RLWServerError;
 use crate::job_processor::*;
-use crate::processing::execute_command;
+use crate::processing::{execute_command, JobSpec};
+use crate::pty::{self, PtySize};
 
-use std::{os::unix::prelude::ExitStatusExt, process::ExitStatus};
+use std::os::unix::prelude::ExitStatusExt;
 
+use std::os::unix::io::{AsRawFd, OwnedFd};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::thread;
+use std::sync::Arc;
+use std::time::Duration;
 
 const COMMAND_DIR: &str = "./tests/test_env";
 
+// Number of historical output chunks a slow subscriber can lag behind before it
+// starts missing broadcasts (it still gets the retained `output` replay either way).
+const OUTPUT_BROADCAST_CAPACITY: usize = 1024;
+
 /// A user job containing information about the
 /// underlying process.
 pub struct Job {
@@ -26,16 +37,128 @@ pub struct Job {
 
     /// Job process ID
     pub pid: Mutex<Option<u32>>,
+
+    /// PTY master fd, set only for jobs started with `CommandType::Shell`. `spawn_pty`'s
+    /// reader task reads from its own duplicate of this fd, so holding it here keeps it
+    /// open for the job's full lifetime rather than only until that task finishes.
+    pub pty_master: Mutex<Option<Arc<OwnedFd>>>,
+
+    /// Channel used to stream bytes into the running process' stdin.
+    pub stdin_tx: Mutex<Option<tokio::sync::mpsc::Sender<Vec<u8>>>>,
+
+    /// Fan-out for output chunks as they're produced, so any number of subscribers can
+    /// follow a job's output live without polling `output` under a lock. Each chunk is
+    /// tagged with its own starting byte offset in the overall stream, so a late
+    /// subscriber can tell whether a chunk it receives live was already included in the
+    /// `output` history it read at subscribe time.
+    output_tx: broadcast::Sender<(usize, Vec<u8>)>,
+
+    /// Flips to `true` once the job's process has exited. `output_tx` lives as long as
+    /// this `Job` does (retained in `JobManager`'s map), so a `stream_output` subscriber
+    /// parked on `rx.recv()` would otherwise never see a `Closed` broadcast once the
+    /// process finishes; this lets it race that recv against the job actually finishing.
+    done_tx: watch::Sender<bool>,
 }
 
 impl Job {
     /// Construct a new Job
     pub fn new() -> Self {
+        let (output_tx, _) = broadcast::channel(OUTPUT_BROADCAST_CAPACITY);
+        let (done_tx, _) = watch::channel(false);
         Self {
             status: Mutex::new(None),
             output: Mutex::new(Vec::new()),
             pid: Mutex::new(None),
+            pty_master: Mutex::new(None),
+            stdin_tx: Mutex::new(None),
+            output_tx,
+            done_tx,
+        }
+    }
+
+    /// Marks the job as finished, waking any `stream_output` callers parked on the live
+    /// broadcast so they can end instead of waiting on a chunk that will never come.
+    fn mark_done(&self) {
+        let _ = self.done_tx.send(true);
+    }
+
+    /// Writes `data` to the stdin of the running process. For a PTY-backed job (see
+    /// `CommandType::Shell`) this writes directly to the PTY master; otherwise it is
+    /// sent down the stdin channel threaded into `execute_command`. Sending an empty
+    /// `data` closes the pipe, signalling EOF to the process.
+    pub async fn write_stdin(&self, data: Vec<u8>) -> Result<(), RLWServerError> {
+        if let Some(master) = self.pty_master.lock().await.as_ref() {
+            nix::unistd::write(master.as_raw_fd(), &data)
+                .map_err(|e| RLWServerError(format!("Unable to write to pty: {:?}", e)))?;
+            return Ok(());
+        }
+
+        let stdin_tx = self.stdin_tx.lock().await;
+        match stdin_tx.as_ref() {
+            Some(tx) => tx
+                .send(data)
+                .await
+                .map_err(|e| RLWServerError(format!("Unable to write to stdin: {:?}", e))),
+            None => Err(RLWServerError("Job has no running process to write to".to_string())),
+        }
+    }
+
+    /// Resizes the terminal of a job started in PTY mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - New terminal dimensions to apply via `TIOCSWINSZ`.
+    pub async fn resize(&self, size: PtySize) -> Result<(), RLWServerError> {
+        let master = self
+            .pty_master
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| RLWServerError("Job has no pty to resize".to_string()))?;
+        pty::resize(master.as_raw_fd(), size)
+    }
+
+    /// Stops this job's process, escalating from a graceful `SIGTERM` to a forceful
+    /// `SIGKILL` if it hasn't exited within `grace`. Also closes the job's stdin, so the
+    /// process sees EOF and later `write_stdin` calls fail.
+    ///
+    /// # Arguments
+    ///
+    /// * `grace` - How long to wait after `SIGTERM` before escalating to `SIGKILL`.
+    pub async fn stop(&self, grace: Duration) -> Result<(), RLWServerError> {
+        let pid = self
+            .pid
+            .lock()
+            .await
+            .ok_or_else(|| RLWServerError("Job has no running process".to_string()))?;
+        let pid = Pid::from_raw(pid as i32);
+
+        signal::kill(pid, Signal::SIGTERM)
+            .map_err(|e| RLWServerError(format!("Unable to send SIGTERM: {:?}", e)))?;
+
+        // Dropping the sender ends the stdin writer task's `rx_stdin.recv()` loop, which
+        // then closes the pipe so the process sees EOF.
+        *self.stdin_tx.lock().await = None;
+
+        let deadline = tokio::time::Instant::now() + grace;
+        while tokio::time::Instant::now() < deadline
+            && matches!(
+                *self.status.lock().await,
+                Some(status_response::ProcessStatus::Running(true))
+            )
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
         }
+
+        if matches!(
+            *self.status.lock().await,
+            Some(status_response::ProcessStatus::Running(true))
+        ) {
+            signal::kill(pid, Signal::SIGKILL)
+                .map_err(|e| RLWServerError(format!("Unable to send SIGKILL: {:?}", e)))?;
+        }
+
+        Ok(())
     }
 
     /// Start a new process, and populate `self` with the pid, output
@@ -43,22 +166,31 @@ impl Job {
     ///
     /// # Arguments
     ///
-    /// * `command`      - Command to execute. Examples: "cargo", "ls", , "/bin/bash"
-    /// * `args`         - Arguments to accompany the command. Examples: "--version", "-a", "./file.sh"
-    /// * `command_type` - Type of grpc request processing a new command. Will be either Start or Stop
+    /// * `spec`         - Command, arguments, working directory and environment for the job.
+    /// * `command_type` - Type of grpc request processing a new command. `Shell` allocates a
+    ///   PTY so the command gets a real terminal session instead of plain pipes. A `Shell`
+    ///   job uses only `spec.command` and `spec.args`: it runs in the server's own working
+    ///   directory and environment, and `limits` is not applied to it.
+    /// * `limits`       - Resource caps applied to the process via cgroups. The process
+    ///   runs uncapped when `None`, and also if the host can't set up the requested cgroup.
     pub async fn new_command(
         &self,
-        command: String,
-        args: Vec<String>,
+        spec: JobSpec,
         command_type: CommandType,
+        limits: Option<JobLimits>,
     ) -> Result<(), RLWServerError> {
-        let (tx_output, rx_output): (Sender<u8>, Receiver<u8>) = mpsc::channel();
+        if let CommandType::Shell(size) = command_type {
+            return self.new_pty_command(spec.command, spec.args, size).await;
+        }
+
+        let (tx_output, rx_output): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
         let (tx_pid, rx_pid): (Sender<u32>, Receiver<u32>) = mpsc::channel();
+        let (tx_stdin, rx_stdin) = tokio::sync::mpsc::channel::<Vec<u8>>(32);
+        *self.stdin_tx.lock().await = Some(tx_stdin);
 
-        // Process job
-        let thread = thread::spawn(move || -> Result<ExitStatus, RLWServerError> {
-            execute_command(command, args, Some(&tx_pid), &tx_output)
-        });
+        // Process job. `execute_command` is itself async, so it's driven on the tokio
+        // runtime rather than a plain OS thread, which would have no executor to poll it.
+        let task = tokio::spawn(execute_command(spec, rx_stdin, limits, Some(tx_pid), tx_output));
 
         if let CommandType::Start = command_type {
             let mut pid = self.pid.lock().await;
@@ -68,20 +200,27 @@ impl Job {
             *status = Some(status_response::ProcessStatus::Running(true))
         }
 
-        // Populate stdout/stderr output
+        // Populate stdout/stderr output and publish it to any live subscribers
         for rec in rx_output {
-            self.output.lock().await.push(rec);
+            let offset = {
+                let mut output = self.output.lock().await;
+                let offset = output.len();
+                output.extend(rec.clone());
+                offset
+            };
+            let _ = self.output_tx.send((offset, rec));
         }
 
         // Process finished
-        let status = thread
-            .join()
-            .map_err(|e| RLWServerError(format!("Error joining on processing thread {:?}", e)))??;
+        let status = task
+            .await
+            .map_err(|e| RLWServerError(format!("Error joining on processing task {:?}", e)))??;
 
         // Finished with signal
         if let Some(s) = status.signal() {
             let mut status = self.status.lock().await;
             *status = Some(status_response::ProcessStatus::Signal(s));
+            self.mark_done();
             return Ok(());
         }
 
@@ -89,43 +228,186 @@ impl Job {
         if let Some(c) = status.code() {
             let mut status = self.status.lock().await;
             *status = Some(status_response::ProcessStatus::ExitCode(c));
+            self.mark_done();
             return Ok(());
         }
 
-        // Thread closed but job had not finished
+        self.mark_done();
+        // Task closed but job had not finished
         Err(RLWServerError(
-            "Job processing thread closed before finishing the job".to_string(),
+            "Job processing task closed before finishing the job".to_string(),
         ))
     }
 
-    pub async fn stream_output(&self) {
-        let mut read_idx: usize = 0;
-        while matches!(
-            *self.status.lock().await,
-            Some(status_response::ProcessStatus::Running(true))
-        ) {
-            let o = self.output.lock().await;
-            // Only read each letter once
-            // If finished reading "so far"
-            // just spin and wait
-            if read_idx < o.len() {
-                // stream o[read_idx]
-                println!("Read idx = {:?}", o[read_idx]);
-                read_idx += 1;
-            } else {
-                // should I sleep here
-            }
+    /// Starts `command` attached to a pseudo-terminal and populates `self` with the pid,
+    /// pty master fd and output from the process, mirroring `new_command`'s bookkeeping.
+    async fn new_pty_command(
+        &self,
+        command: String,
+        args: Vec<String>,
+        size: PtySize,
+    ) -> Result<(), RLWServerError> {
+        let (tx_output, rx_output): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
+        let (tx_pid, rx_pid): (Sender<u32>, Receiver<u32>) = mpsc::channel();
+
+        let (master, child) =
+            pty::spawn_pty(command, args, size, Some(tx_pid), tx_output).await?;
+
+        *self.pty_master.lock().await = Some(master);
+        *self.pid.lock().await = Some(rx_pid.recv()?);
+        *self.status.lock().await = Some(status_response::ProcessStatus::Running(true));
+
+        for rec in rx_output {
+            let offset = {
+                let mut output = self.output.lock().await;
+                let offset = output.len();
+                output.extend(rec.clone());
+                offset
+            };
+            let _ = self.output_tx.send((offset, rec));
+        }
+
+        let status = child
+            .await
+            .map_err(|e| RLWServerError(format!("Error joining on pty processing task {:?}", e)))??;
+
+        if let Some(s) = status.signal() {
+            *self.status.lock().await = Some(status_response::ProcessStatus::Signal(s));
+            self.mark_done();
+            return Ok(());
         }
 
-        // In the event that the process is no longer running,
-        // but the output wasn't finished being streamed, stream
-        // the rest.
-        let o = self.output.lock().await;
-        while read_idx < o.len() {
-            println!("Read idx after = {:?}", o[read_idx]);
-            read_idx += 1;
+        if let Some(c) = status.code() {
+            *self.status.lock().await = Some(status_response::ProcessStatus::ExitCode(c));
+            self.mark_done();
+            return Ok(());
         }
+
+        self.mark_done();
+        Err(RLWServerError(
+            "Pty processing task closed before finishing the job".to_string(),
+        ))
+    }
+
+    /// Subscribes to this job's output. Returns everything recorded so far together
+    /// with a receiver for everything broadcast from this point on, so a late
+    /// subscriber sees the full history followed by a live tail with no gap. A chunk
+    /// produced in the gap between subscribing and reading the retained buffer can
+    /// still end up in both; its offset tag is what lets `stream_output` tell the two
+    /// apart rather than sending it twice.
+    pub async fn subscribe(&self) -> (Vec<u8>, broadcast::Receiver<(usize, Vec<u8>)>) {
+        // Subscribe before reading the retained buffer: a chunk produced in between
+        // may end up in both, but none can be missed by reading in the other order.
+        let rx = self.output_tx.subscribe();
+        let history = self.output.lock().await.clone();
+        (history, rx)
+    }
+
+    /// Returns a stream of this job's output: history first, then a live tail, ending
+    /// once the job is no longer running. Any number of callers may call this
+    /// concurrently; each gets its own independent subscription. Takes `self` as an
+    /// `Arc` (rather than `&self`) so the returned stream can outlive the caller's own
+    /// reference to the job, which `JobManager::stream` relies on.
+    pub async fn stream_output(self: Arc<Self>) -> impl Stream<Item = Vec<u8>> {
+        let (history, rx) = self.subscribe().await;
+        let done_rx = self.done_tx.subscribe();
+        let emitted = history.len();
+
+        let live = stream::unfold(
+            (self, rx, done_rx, emitted),
+            |(job, mut rx, mut done_rx, mut emitted)| async move {
+                loop {
+                    // Once the job is done, `output_tx` will never produce another
+                    // broadcast (its sender lives on `job`, retained by `JobManager` for
+                    // as long as the job is tracked at all), so only drain what's left
+                    // without blocking rather than racing a `recv` that'll never resolve.
+                    if *done_rx.borrow() {
+                        return match rx.try_recv() {
+                            Ok((offset, chunk)) => {
+                                let (new_emitted, out) =
+                                    skip_already_emitted(emitted, offset, chunk);
+                                emitted = new_emitted;
+                                match out {
+                                    Some(chunk) => Some((chunk, (job, rx, done_rx, emitted))),
+                                    None => continue,
+                                }
+                            }
+                            Err(broadcast::error::TryRecvError::Lagged(_)) => {
+                                match recover_lagged(&job, &mut emitted).await {
+                                    Some(chunk) => Some((chunk, (job, rx, done_rx, emitted))),
+                                    None => continue,
+                                }
+                            }
+                            Err(_) => None,
+                        };
+                    }
+
+                    tokio::select! {
+                        biased;
+                        recv = rx.recv() => match recv {
+                            Ok((offset, chunk)) => {
+                                let (new_emitted, out) =
+                                    skip_already_emitted(emitted, offset, chunk);
+                                emitted = new_emitted;
+                                match out {
+                                    Some(chunk) => return Some((chunk, (job, rx, done_rx, emitted))),
+                                    None => continue,
+                                }
+                            }
+                            Err(broadcast::error::RecvError::Lagged(_)) => {
+                                match recover_lagged(&job, &mut emitted).await {
+                                    Some(chunk) => return Some((chunk, (job, rx, done_rx, emitted))),
+                                    None => continue,
+                                }
+                            }
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        },
+                        // Re-check the `done` and buffered-broadcast state from the top
+                        // of the loop rather than acting here directly.
+                        _ = done_rx.changed() => continue,
+                    }
+                }
+            },
+        );
+
+        stream::once(async move { history }).chain(live)
+    }
+}
+
+/// Recovers from a subscriber falling behind the broadcast channel by replaying
+/// whatever bytes past `*emitted` are still sitting in the job's retained `output`
+/// buffer, rather than silently skipping the gap. Returns `None` (nothing to emit,
+/// caller should keep looping) if the buffer hasn't grown past what was already sent.
+async fn recover_lagged(job: &Arc<Job>, emitted: &mut usize) -> Option<Vec<u8>> {
+    let missed = job
+        .output
+        .lock()
+        .await
+        .get(*emitted..)
+        .unwrap_or(&[])
+        .to_vec();
+    if missed.is_empty() {
+        return None;
+    }
+    *emitted += missed.len();
+    Some(missed)
+}
+
+/// Gates a live broadcast chunk against what's already been emitted from history, since
+/// a chunk produced in the gap between `subscribe`'s two steps can land in both. `offset`
+/// is the chunk's starting position in the overall output stream; anything at or before
+/// `emitted` has already gone out, so it's trimmed (or dropped entirely) rather than
+/// resent. Returns the updated `emitted` alongside whatever's left of `chunk`, if any.
+fn skip_already_emitted(emitted: usize, offset: usize, chunk: Vec<u8>) -> (usize, Option<Vec<u8>>) {
+    let end = offset + chunk.len();
+    let new_emitted = emitted.max(end);
+    if end <= emitted {
+        return (new_emitted, None);
+    }
+    if offset >= emitted {
+        return (new_emitted, Some(chunk));
     }
+    (new_emitted, Some(chunk[(emitted - offset)..].to_vec()))
 }
 
 pub enum CommandType {
@@ -133,6 +415,9 @@ pub enum CommandType {
     Stop,
     Stream,
     Status,
+    /// Run the command attached to a pseudo-terminal of the given size, for
+    /// interactive sessions (shells, `top`, line-editing programs).
+    Shell(PtySize),
 }
 
 #[cfg(test)]
@@ -149,9 +434,14 @@ mod tests {
         let arc1 = Arc::clone(&job_arc);
         let task1 = tokio::spawn(async move {
             arc1.new_command(
-                "/bin/bash".to_string(),
-                vec!["./test2.sh".to_string()],
+                JobSpec {
+                    command: "/bin/bash".to_string(),
+                    args: vec!["./test2.sh".to_string()],
+                    cwd: None,
+                    env: Vec::new(),
+                },
                 CommandType::Start,
+                None,
             )
             .await
             .expect("bad in here");
@@ -160,7 +450,7 @@ mod tests {
         tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
         let arc2 = Arc::clone(&job_arc);
         let task2 = tokio::spawn(async move {
-            arc2.stream_output().await;
+            arc2.stream_output().await.for_each(|_| async {}).await;
         });
 
         tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
@@ -169,9 +459,14 @@ mod tests {
         let pid = arc2.pid.lock().await.expect("no pid");
         let task2 = tokio::spawn(async move {
             arc3.new_command(
-                "kill".to_string(),
-                vec!["-9".to_string(), pid.to_string()],
+                JobSpec {
+                    command: "kill".to_string(),
+                    args: vec!["-9".to_string(), pid.to_string()],
+                    cwd: None,
+                    env: Vec::new(),
+                },
                 CommandType::Start,
+                None,
             )
             .await
             .expect("bad in here");
@@ -1,52 +1,133 @@
 //! Exposes the command processing logic to the job module.
 
+use crate::cgroups::{JobCgroup, JobLimits};
 use crate::errors::RLWServerError;
 
+use std::path::PathBuf;
 use std::process::{ExitStatus, Stdio};
 use std::sync::mpsc::Sender;
-use tokio::io::{AsyncReadExt, BufReader};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::mpsc::Receiver as StdinReceiver;
 use tokio::task::JoinHandle;
 
-// Path to the directory where the processes will be run.
-// TODO: Make this a configurable part of the server.
-const PROCESS_DIR_PATH: &str = "./tests/test_env";
+// Directory processes run in when a `JobSpec` doesn't specify its own `cwd`.
+const DEFAULT_PROCESS_DIR_PATH: &str = "./tests/test_env";
 
 // Upper limit on size of chunks sent down output channel
 const OUTPUT_CHUNK_SIZE_BYTES: usize = 1024;
 
-/// Executes a command using the arguments provided and sends the output results down the provided channel.
+/// Describes a job to run: the command and arguments, and the working directory and
+/// environment to run it with, rather than pinning every job to a fixed sandbox.
+#[derive(Debug, Clone)]
+pub struct JobSpec {
+    /// Command to execute. Examples: "cargo", "ls", "/bin/bash".
+    pub command: String,
+    /// Arguments to accompany the command. Examples: "--version", "-a", "./file.sh".
+    pub args: Vec<String>,
+    /// Working directory for the process. Defaults to `DEFAULT_PROCESS_DIR_PATH` when `None`.
+    pub cwd: Option<PathBuf>,
+    /// Extra environment variables to set on the process, in addition to the caller's own.
+    pub env: Vec<(String, String)>,
+}
+
+/// Executes a job using the arguments provided and sends the output results down the provided channel.
 ///
 /// # Arguments
 ///
-/// * `command`   - Command to execute. Examples: "cargo", "ls", "/bin/bash".
-/// * `args`      - Arguments to accompany the command. Examples: "--version", "-a", "./file.sh".
+/// * `spec`      - Command, arguments, working directory and environment for the job.
+/// * `rx_stdin`  - Channel of byte chunks to write to the process' stdin. An empty chunk,
+///                 or the sender being dropped, closes the pipe so the process sees EOF.
+/// * `limits`    - Resource caps applied to the process via a dedicated cgroup v2
+///                 directory. Left uncapped when `None`; a cgroup that fails to set up
+///                 (e.g. `/sys/fs/cgroup` isn't delegated to this process) is logged and
+///                 skipped rather than failing the job.
 /// * `tx_pid`    - The channel producer used to send the process PID of the job started.
 /// * `tx_output` - The channel producer used to stream the command results
 
 pub async fn execute_command(
-    command: String,
-    args: Vec<String>,
+    spec: JobSpec,
+    mut rx_stdin: StdinReceiver<Vec<u8>>,
+    limits: Option<JobLimits>,
     tx_pid: Option<Sender<u32>>,
     tx_output: Sender<Vec<u8>>,
 ) -> Result<ExitStatus, RLWServerError> {
+    let cwd = match spec.cwd {
+        Some(cwd) if cwd.is_dir() => cwd,
+        Some(cwd) => {
+            return Err(RLWServerError(format!(
+                "cwd {:?} does not exist or is not a directory",
+                cwd
+            )))
+        }
+        None => PathBuf::from(DEFAULT_PROCESS_DIR_PATH),
+    };
+
     // Start process
-    let mut output = Command::new(command)
-        .args(args)
-        .current_dir(PROCESS_DIR_PATH)
+    let mut output = Command::new(spec.command)
+        .args(spec.args)
+        .current_dir(cwd)
+        .envs(spec.env)
+        .stdin(Stdio::piped())
         .stderr(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()?;
 
+    let pid = output
+        .id()
+        .ok_or_else(|| RLWServerError("Unable to get process pid".to_string()))?;
+
+    // Cap the process' resource usage so a single runaway job can't starve the host.
+    // Only attempted when the caller opts in with explicit limits: cgroup delegation
+    // isn't available on every host (ordinary dev boxes, unprivileged CI), and a job
+    // shouldn't fail outright just because the optional resource cap couldn't be set up.
+    let cgroup = match limits {
+        Some(limits) => match JobCgroup::create(pid, limits) {
+            Ok(cgroup) => match cgroup.add_process(pid) {
+                Ok(()) => Some(cgroup),
+                Err(e) => {
+                    eprintln!(
+                        "Unable to apply resource limits for pid {}, continuing without them: {:?}",
+                        pid, e
+                    );
+                    None
+                }
+            },
+            Err(e) => {
+                eprintln!(
+                    "Unable to apply resource limits for pid {}, continuing without them: {:?}",
+                    pid, e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
     // Send PID
     if let Some(t) = tx_pid {
-        t.send(
-            output
-                .id()
-                .ok_or(RLWServerError("Unable to get process pid".to_string()))?,
-        )?;
+        t.send(pid)?;
     }
 
+    // Write stdin concurrently with the stdout/stderr readers below: the child can block
+    // writing its own stdout while we'd otherwise block writing its stdin, so these must
+    // run as independent tasks rather than one after the other.
+    let mut stdin_writer = output
+        .stdin
+        .take()
+        .ok_or_else(|| RLWServerError("Unable to write to stdin stream".to_string()))?;
+    let stdin_thread: JoinHandle<Result<(), RLWServerError>> = tokio::spawn(async move {
+        while let Some(data) = rx_stdin.recv().await {
+            if data.is_empty() {
+                break;
+            }
+            stdin_writer.write_all(&data).await?;
+        }
+        // Dropping the writer closes the pipe, signalling EOF to the child.
+        drop(stdin_writer);
+        Ok(())
+    });
+
     // Setup stream readers
     let mut stdout_reader = BufReader::with_capacity(
         OUTPUT_CHUNK_SIZE_BYTES,
@@ -101,6 +182,22 @@ pub async fn execute_command(
 
     // Return exit code or terminating signal
     let status = output.wait().await?;
+
+    // The stdin writer task only stops once `rx_stdin.recv()` returns `None`, which
+    // requires its `Sender` to be dropped -- but that `Sender` is retained by `Job` for
+    // the job's whole lifetime, not just while it's running, and is only ever cleared
+    // by an explicit `Job::stop`. Awaiting the writer task here would deadlock on every
+    // job that exits on its own. The process has now exited, so there's nothing left to
+    // write to; cancel the writer instead of waiting on it.
+    stdin_thread.abort();
+
+    // The kernel only allows removing a cgroup once it has no processes left in it.
+    if let Some(cgroup) = cgroup {
+        if let Err(e) = cgroup.remove() {
+            eprintln!("Unable to clean up cgroup for pid {}: {:?}", pid, e);
+        }
+    }
+
     Ok(status)
 }
 
@@ -119,12 +216,17 @@ mod tests {
         // Setup
         let (tx_output, rx_output): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
         let (tx_pid, rx_pid): (Sender<u32>, Receiver<u32>) = mpsc::channel();
-        let command = "/bin/bash".to_string();
-        let args = vec![TESTING_SCRIPTS_DIR.to_string() + "start_process.sh"];
+        let (_tx_stdin, rx_stdin) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
+        let spec = JobSpec {
+            command: "/bin/bash".to_string(),
+            args: vec![TESTING_SCRIPTS_DIR.to_string() + "start_process.sh"],
+            cwd: None,
+            env: Vec::new(),
+        };
 
         // Test command execution
         let t1: JoinHandle<Result<(), RLWServerError>> = tokio::spawn(async move {
-            execute_command(command, args, Some(tx_pid), tx_output).await?;
+            execute_command(spec, rx_stdin, None, Some(tx_pid), tx_output).await?;
             Ok(())
         });
 
@@ -155,11 +257,16 @@ mod tests {
         // Setup
         let (tx_output, _rx_output): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
         let (tx_pid, _rx_input): (Sender<u32>, Receiver<u32>) = mpsc::channel();
-        let command = "!i_am_a_bad_command!".to_string();
-        let args = vec!["-abc".to_string()];
+        let (_tx_stdin, rx_stdin) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
+        let spec = JobSpec {
+            command: "!i_am_a_bad_command!".to_string(),
+            args: vec!["-abc".to_string()],
+            cwd: None,
+            env: Vec::new(),
+        };
 
         // Expected failure: "No such file or directory (os error 2)"
-        assert!(execute_command(command, args, Some(tx_pid), tx_output)
+        assert!(execute_command(spec, rx_stdin, None, Some(tx_pid), tx_output)
             .await
             .is_err());
         Ok(())
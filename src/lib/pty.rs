@@ -0,0 +1,172 @@
+//! Pseudo-terminal allocation for interactive, `CommandType::Shell` jobs.
+
+use crate::errors::RLWServerError;
+
+use nix::pty::{openpty, Winsize};
+use nix::unistd::setsid;
+
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Stdio;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+/// Terminal dimensions for a PTY-backed job.
+///
+/// Mirrors the fields of the `TIOCSWINSZ` `winsize` struct; pixel dimensions
+/// are left at zero since no client of this crate renders glyphs itself.
+#[derive(Debug, Clone, Copy)]
+pub struct PtySize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl From<PtySize> for Winsize {
+    fn from(size: PtySize) -> Self {
+        Winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+/// Spawns `command` attached to a freshly allocated pseudo-terminal rather than plain
+/// pipes, so interactive programs (shells, `top`, anything using line editing or colors)
+/// behave as they would over a real terminal. The combined stdout/stderr/stdin of the
+/// child is the PTY itself, so output is streamed as a single interleaved byte stream.
+///
+/// # Arguments
+///
+/// * `command`   - Command to execute. Examples: "/bin/bash", "top".
+/// * `args`      - Arguments to accompany the command.
+/// * `size`      - Initial terminal dimensions for the PTY.
+/// * `tx_pid`    - The channel producer used to send the process PID of the job started.
+/// * `tx_output` - The channel producer used to stream the PTY output.
+///
+/// Returns the PTY master fd, shared via `Arc` so the caller (the job's own state) can
+/// retain it for the job's whole lifetime without it being closed out from under them
+/// the moment the reader task below finishes with its own handle, alongside the task
+/// driving the child to completion.
+pub async fn spawn_pty(
+    command: String,
+    args: Vec<String>,
+    size: PtySize,
+    tx_pid: Option<Sender<u32>>,
+    tx_output: Sender<Vec<u8>>,
+) -> Result<
+    (
+        Arc<OwnedFd>,
+        JoinHandle<Result<std::process::ExitStatus, RLWServerError>>,
+    ),
+    RLWServerError,
+> {
+    let pty = openpty(Some(&Winsize::from(size)), None)
+        .map_err(|e| RLWServerError(format!("Unable to allocate pty: {:?}", e)))?;
+    let master = Arc::new(pty.master);
+    let slave: OwnedFd = pty.slave;
+    let master_fd = master.as_raw_fd();
+    let slave_fd = slave.as_raw_fd();
+
+    // Each of stdin/stdout/stderr takes ownership of the fd it's given and closes it
+    // independently, so handing `slave_fd` to all three (on top of `slave` itself
+    // owning it) would close the same fd number up to four times. On the multi_thread
+    // runtime another task can have opened a new fd with that number in between, so a
+    // later redundant close could end up closing an unrelated fd. Give each stdio
+    // stream its own duplicate instead.
+    let dup_slave = |stream: &str| -> Result<RawFd, RLWServerError> {
+        nix::unistd::dup(slave_fd).map_err(|e| {
+            RLWServerError(format!("Unable to duplicate pty slave for {}: {:?}", stream, e))
+        })
+    };
+
+    let mut command = Command::new(command);
+    command
+        .args(args)
+        .stdin(unsafe { Stdio::from_raw_fd(dup_slave("stdin")?) })
+        .stdout(unsafe { Stdio::from_raw_fd(dup_slave("stdout")?) })
+        .stderr(unsafe { Stdio::from_raw_fd(dup_slave("stderr")?) });
+
+    // Give the child its own session and make the slave its controlling terminal
+    // before exec, so job-control signals and line discipline behave as expected.
+    unsafe {
+        command.pre_exec(move || {
+            setsid().map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+            set_controlling_terminal(slave_fd, 0)
+                .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+            Ok(())
+        });
+    }
+
+    let mut child = command
+        .spawn()
+        .map_err(|e| RLWServerError(format!("Unable to spawn pty process: {:?}", e)))?;
+    // The child now owns the slave side; drop our copy so the master sees EOF on exit.
+    drop(slave);
+
+    if let Some(t) = tx_pid {
+        t.send(
+            child
+                .id()
+                .ok_or_else(|| RLWServerError("Unable to get process pid".to_string()))?,
+        )?;
+    }
+
+    // The reader task below needs its own fd to hand to `tokio::fs::File`, which closes
+    // whatever fd it owns as soon as it's dropped. Duplicate one for it rather than
+    // handing over `master` itself, so the `Arc<OwnedFd>` returned to the caller stays
+    // open — and usable for a later `resize`/`write_stdin` — for the job's full
+    // lifetime, not just until the reader task's post-exit flush.
+    let reader_fd = nix::unistd::dup(master_fd)
+        .map_err(|e| RLWServerError(format!("Unable to duplicate pty master: {:?}", e)))?;
+    // SAFETY: `reader_fd` is a valid, open fd we just duplicated and own exclusively.
+    let mut master_file = unsafe { tokio::fs::File::from_raw_fd(reader_fd) };
+
+    let thread: JoinHandle<Result<std::process::ExitStatus, RLWServerError>> =
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                match master_file.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(size) => {
+                        if tx_output.send(buf[0..size].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    // The PTY master returns EIO once the slave side has no readers left.
+                    Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+                    Err(e) => {
+                        return Err(RLWServerError(format!("Error reading from pty: {:?}", e)))
+                    }
+                }
+            }
+            let _ = master_file.flush().await;
+            child
+                .wait()
+                .await
+                .map_err(|e| RLWServerError(format!("Error waiting on pty child: {:?}", e)))
+        });
+
+    Ok((master, thread))
+}
+
+/// Resizes the terminal backing a running PTY job by issuing `TIOCSWINSZ` on its master fd.
+pub fn resize(master_fd: RawFd, size: PtySize) -> Result<(), RLWServerError> {
+    let winsize: Winsize = size.into();
+
+    // SAFETY: `master_fd` is a valid, open PTY master fd for the lifetime of the job,
+    // and `winsize` is a plain repr(C) struct matching the ioctl's expected layout.
+    let result = unsafe { set_winsize(master_fd, &winsize) };
+    result.map_err(|e| RLWServerError(format!("Unable to resize pty: {:?}", e)))
+}
+
+nix::ioctl_write_ptr_bad!(set_winsize, libc::TIOCSWINSZ, Winsize);
+// Makes the slave referred to by `fd` the calling process' controlling terminal; must
+// run after `setsid()`, since the syscall only succeeds for a session leader that
+// doesn't already have one.
+nix::ioctl_write_int_bad!(set_controlling_terminal, libc::TIOCSCTTY);
@@ -0,0 +1,137 @@
+//! Transport-level encryption and authentication for the gRPC server.
+//!
+//! Every connection establishes a shared key via X25519 Diffie-Hellman against the
+//! server's identity key, then each message is framed as an XChaCha20Poly1305 AEAD
+//! (random 24-byte nonce per frame, associated data binding the plaintext length) so
+//! a command can't reach `execute_command` over a connection whose frames aren't
+//! confidential and tamper-evident.
+//!
+//! The DH handshake alone only authenticates the *server* to a client holding its
+//! public key; any client can supply an ephemeral key and complete it. Pinning
+//! `ServerConfig` to a set of allowed client keys (`with_allowed_clients`) is what
+//! authenticates the *client*, so an unrecognized peer never gets a usable cipher in
+//! the first place.
+
+use crate::errors::RLWServerError;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const NONCE_LEN: usize = 24;
+
+/// Server-side transport configuration: a long-lived X25519 identity key used to
+/// derive a shared AEAD key with each connecting client, plus an optional allowlist of
+/// client public keys `establish` will accept.
+pub struct ServerConfig {
+    identity: StaticSecret,
+    allowed_clients: Vec<[u8; 32]>,
+}
+
+impl ServerConfig {
+    /// Generates a fresh server identity key. No client allowlist is set, so `establish`
+    /// accepts any client key until pinned with `with_allowed_clients` — see there for
+    /// why an anonymous DH handshake alone doesn't authenticate the client.
+    pub fn new() -> Self {
+        Self {
+            identity: StaticSecret::new(OsRng),
+            allowed_clients: Vec::new(),
+        }
+    }
+
+    /// Builds a config around a caller-supplied identity key, for servers that need a
+    /// stable identity across restarts.
+    pub fn from_identity(identity: StaticSecret) -> Self {
+        Self {
+            identity,
+            allowed_clients: Vec::new(),
+        }
+    }
+
+    /// Restricts `establish` to only the given client public keys, rejecting anyone
+    /// else's handshake. Without this, ECDH alone authenticates the server to the
+    /// client but not the other way around: any peer can supply an ephemeral key and
+    /// derive a valid cipher, so spawning a process isn't actually gated on identity.
+    pub fn with_allowed_clients(mut self, allowed_clients: impl IntoIterator<Item = PublicKey>) -> Self {
+        self.allowed_clients = allowed_clients.into_iter().map(|k| *k.as_bytes()).collect();
+        self
+    }
+
+    /// The public half of this server's identity key, sent to clients during the handshake.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.identity)
+    }
+
+    /// Performs ECDH against a client's public key and derives the XChaCha20Poly1305
+    /// cipher used to encrypt that connection, rejecting the handshake outright if an
+    /// allowlist is configured and `client_public` isn't on it.
+    pub fn establish(&self, client_public: PublicKey) -> Result<XChaCha20Poly1305, RLWServerError> {
+        if !self.allowed_clients.is_empty()
+            && !self
+                .allowed_clients
+                .iter()
+                .any(|allowed| allowed == client_public.as_bytes())
+        {
+            return Err(RLWServerError(
+                "Client public key is not authorized".to_string(),
+            ));
+        }
+
+        let shared_secret = self.identity.diffie_hellman(&client_public);
+        Ok(XChaCha20Poly1305::new(&derive_key(shared_secret.as_bytes())))
+    }
+}
+
+/// Derives a 256-bit AEAD key from a raw X25519 shared secret so the key fed to the
+/// cipher is uniformly random rather than directly exposing DH output.
+fn derive_key(shared_secret: &[u8; 32]) -> Key {
+    *Key::from_slice(&Sha256::digest(shared_secret))
+}
+
+/// Encrypts `plaintext` into a single authenticated frame: a random nonce followed by
+/// the ciphertext and AEAD tag. The plaintext's length is bound in as associated data
+/// so a truncated or padded frame fails authentication rather than being misread.
+pub fn encrypt_frame(
+    cipher: &XChaCha20Poly1305,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, RLWServerError> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = (plaintext.len() as u64).to_be_bytes();
+
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &aad,
+            },
+        )
+        .map_err(|e| RLWServerError(format!("Unable to encrypt frame: {:?}", e)))?;
+
+    let mut frame = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    frame.extend_from_slice(&nonce);
+    frame.extend_from_slice(&ciphertext);
+    Ok(frame)
+}
+
+/// Decrypts and authenticates a frame produced by `encrypt_frame`, rejecting it
+/// outright (without exposing any of its bytes) if the AEAD tag doesn't check out.
+pub fn decrypt_frame(cipher: &XChaCha20Poly1305, frame: &[u8]) -> Result<Vec<u8>, RLWServerError> {
+    if frame.len() < NONCE_LEN {
+        return Err(RLWServerError("Frame too short to contain a nonce".to_string()));
+    }
+    let (nonce_bytes, ciphertext) = frame.split_at(NONCE_LEN);
+    let nonce = XNonce::from_slice(nonce_bytes);
+    let aad = (ciphertext.len().saturating_sub(16) as u64).to_be_bytes();
+
+    cipher
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| RLWServerError("Frame failed authentication".to_string()))
+}